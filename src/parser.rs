@@ -1,13 +1,13 @@
 use crate::document::{Document, Node};
 use crate::element::Element;
 use crate::error::{Error, Result};
-use encoding_rs::Decoder;
+use encoding_rs::{CoderResult, Decoder, Encoder};
 use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
 use quick_xml::events::{BytesDecl, BytesStart, Event};
 use quick_xml::Reader;
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::io::{BufRead, Read};
+use std::io::{BufRead, Read, Write};
 
 #[cfg(debug_assertions)]
 macro_rules! debug {
@@ -16,6 +16,24 @@ macro_rules! debug {
     };
 }
 
+// Marker payload for the `io::Error` strict decoding raises, so that a `From<io::Error>`
+// impl can recognize it (via `std::io::Error::get_ref()` and
+// `downcast_ref::<NonDecodableError>()`) and
+// map it to a dedicated variant rather than a generic IO error.
+#[derive(Debug)]
+pub(crate) struct NonDecodableError;
+
+impl std::fmt::Display for NonDecodableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "encountered a byte sequence that is not valid in the declared encoding"
+        )
+    }
+}
+
+impl std::error::Error for NonDecodableError {}
+
 pub(crate) struct DecodeReader<R: Read> {
     decoder: Option<Decoder>,
     inner: R,
@@ -27,6 +45,8 @@ pub(crate) struct DecodeReader<R: Read> {
     decoded_pos: usize,
     decoded_cap: usize,
     done: bool,
+    strict: bool,
+    pending_error: Option<std::io::Error>,
 }
 
 impl<R: Read> DecodeReader<R> {
@@ -43,6 +63,8 @@ impl<R: Read> DecodeReader<R> {
             decoded_pos: 0,
             decoded_cap: 0,
             done: false,
+            strict: false,
+            pending_error: None,
         }
     }
 
@@ -51,8 +73,21 @@ impl<R: Read> DecodeReader<R> {
         self.done = false;
     }
 
+    // When set, a byte sequence that can't be represented in the declared encoding
+    // turns into a hard error instead of being silently replaced with U+FFFD.
+    pub(crate) fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    fn non_decodable_error() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, NonDecodableError)
+    }
+
     // Call this only when decoder is Some
     fn fill_buf_decode(&mut self) -> std::io::Result<&[u8]> {
+        if let Some(err) = self.pending_error.take() {
+            return Err(err);
+        }
         if self.decoded_pos >= self.decoded_cap {
             debug_assert!(self.decoded_pos == self.decoded_cap);
             if self.done {
@@ -72,7 +107,7 @@ impl<R: Read> DecodeReader<R> {
             }
 
             // Fill decoded buffer
-            let (_res, read, written, _replaced) = self.decoder.as_mut().unwrap().decode_to_utf8(
+            let (_res, read, written, replaced) = self.decoder.as_mut().unwrap().decode_to_utf8(
                 &self.undecoded[self.undecoded_pos..self.undecoded_cap],
                 &mut self.decoded,
                 self.done,
@@ -80,6 +115,12 @@ impl<R: Read> DecodeReader<R> {
             self.undecoded_pos += read;
             self.decoded_cap = written;
             self.decoded_pos = 0;
+            if self.strict && replaced {
+                // Keep the error around in case a caller reaches us through `Read::read`
+                // instead of `BufRead::fill_buf` after this point.
+                self.pending_error = Some(Self::non_decodable_error());
+                return Err(Self::non_decodable_error());
+            }
         }
         Ok(&self.decoded[self.decoded_pos..self.decoded_cap])
     }
@@ -96,6 +137,9 @@ impl<R: Read> DecodeReader<R> {
 
 impl<R: Read> Read for DecodeReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(err) = self.pending_error.take() {
+            return Err(err);
+        }
         (&self.decoded[..]).read(buf)
     }
 }
@@ -120,11 +164,99 @@ impl<R: Read> BufRead for DecodeReader<R> {
     }
 }
 
+/// The output-side counterpart of [`DecodeReader`]. Wraps a [`Write`] and transcodes
+/// UTF-8 bytes written to it into the output encoding of an [`encoding_rs::Encoder`],
+/// so a document that was read in a non-UTF-8 encoding such as windows-1252 can be
+/// serialized back into that same encoding instead of always being forced to UTF-8.
+/// UTF-16 is not among the encodings it writes: `encoding_rs` gives `UTF_16LE` and
+/// `UTF_16BE` an encoder whose output encoding is UTF-8, so text written through such
+/// an encoder comes out as UTF-8.
+///
+/// The parser records what a serializer needs to make use of this: [`Document::encoding`]
+/// (the declared, non-UTF-8 encoding) and [`Document::bom`] (the leading byte-order mark,
+/// if any). Actually routing serialization through `EncodeWriter` and re-emitting a
+/// matching `encoding="..."`/BOM is the document serializer's job and lives outside this
+/// module.
+pub(crate) struct EncodeWriter<W: Write> {
+    encoder: Encoder,
+    inner: W,
+    encoded: [u8; 4096],
+    // A UTF-8 character split across two `write()` calls is held here until the rest
+    // of its bytes arrive, since each call must hand `encode_from_utf8` a valid &str.
+    pending: Vec<u8>,
+}
+
+impl<W: Write> EncodeWriter<W> {
+    pub(crate) fn new(inner: W, encoder: Encoder) -> EncodeWriter<W> {
+        EncodeWriter {
+            encoder,
+            inner,
+            encoded: [0; 4096],
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for EncodeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let combined = std::mem::take(&mut self.pending);
+
+        let (valid_len, incomplete_tail) = match std::str::from_utf8(&combined) {
+            Ok(_) => (combined.len(), None),
+            Err(e) if e.error_len().is_none() => {
+                // Valid up to `valid_up_to`, with an incomplete sequence trailing at the
+                // end of the buffer - hold it back for the next `write()` call.
+                let valid_up_to = e.valid_up_to();
+                (valid_up_to, Some(combined[valid_up_to..].to_vec()))
+            }
+            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        };
+
+        let input = std::str::from_utf8(&combined[..valid_len]).unwrap();
+        let mut rest = input;
+        loop {
+            let (result, read, written, _had_errors) =
+                self.encoder
+                    .encode_from_utf8(rest, &mut self.encoded, false);
+            self.inner.write_all(&self.encoded[..written])?;
+            rest = &rest[read..];
+            if result == CoderResult::InputEmpty {
+                break;
+            }
+        }
+        if let Some(tail) = incomplete_tail {
+            self.pending = tail;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.pending.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "incomplete UTF-8 sequence at end of stream",
+            ));
+        }
+        loop {
+            let (result, _read, written, _had_errors) =
+                self.encoder.encode_from_utf8("", &mut self.encoded, true);
+            self.inner.write_all(&self.encoded[..written])?;
+            if result == CoderResult::InputEmpty {
+                break;
+            }
+        }
+        self.inner.flush()
+    }
+}
+
 /// Options when parsing xml.
 ///
 /// `empty_text_node`: true - <tag></tag> will have a Node::Text("") as its children, while <tag /> won't.
 ///
 /// `trim_text`: true - trims leading and ending whitespaces in Node::Text.
+/// An element carrying `xml:space="preserve"` keeps its whitespace regardless of this
+/// setting, until a descendant reverts with `xml:space="default"`.
 ///
 /// `require_decl`: true - Returns error if document doesn't start with XML declaration.
 /// If this is set to false, the parser won't be able to decode encodings other than UTF-8, unless `encoding` below is set.
@@ -132,12 +264,24 @@ impl<R: Read> BufRead for DecodeReader<R> {
 /// `encoding`: None - If this is set, the parser will start reading with this encoding.
 /// But it will switch to XML declaration's encoding value if it has a different value.
 /// See [`encoding_rs::Encoding::for_label`] for valid values.
+///
+/// `strict`: false - If true, a byte sequence that can't be decoded in the document's
+/// encoding raises a hard error (tagged [`NonDecodableError`], intended to surface as
+/// `Error::NonDecodable`) instead of silently turning into U+FFFD.
+///
+/// `detect_bom`: true - If true, a leading UTF-8 or UTF-16 byte-order mark is sniffed,
+/// consumed, and recorded on [`Document::bom`] so it can be reproduced on serialization.
+/// Set to false to skip BOM detection entirely and feed the raw bytes to the decoder.
+/// This doesn't affect detection of BOM-less UTF-16 (sniffed from the declaration's
+/// `<?` pattern), which always runs regardless of this setting.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ReadOptions {
     pub empty_text_node: bool,
     pub trim_text: bool,
     pub require_decl: bool,
     pub encoding: Option<String>,
+    pub strict: bool,
+    pub detect_bom: bool,
 }
 
 impl ReadOptions {
@@ -147,29 +291,59 @@ impl ReadOptions {
             trim_text: true,
             require_decl: true,
             encoding: None,
+            strict: false,
+            detect_bom: true,
         }
     }
 }
 
+/// The byte-order mark a document started with, if any, recorded on [`Document::bom`]
+/// so that serializing the document back out can reproduce it byte-for-byte by default.
+/// Re-emitting it (and any API to override or strip it before writing) is the document
+/// serializer's responsibility; this parser only detects and records it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bom {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+// Caps on entity expansion to defend against "billion laughs" style expansion bombs.
+const MAX_ENTITY_DEPTH: usize = 20;
+const MAX_EXPANDED_ENTITY_LEN: usize = 1 << 20;
+
 //TODO: don't unwrap element_stack.last() or pop(). Invalid XML file can crash the software.
 pub(crate) struct DocumentParser {
     document: Document,
     read_opts: ReadOptions,
     encoding: Option<&'static Encoding>,
     element_stack: Vec<Element>,
+    entities: HashMap<String, String>,
+    // Tracks the effective `xml:space` setting for each open element: true means
+    // "preserve" is in effect, false means normal trimming rules apply.
+    space_stack: Vec<bool>,
+    bom: Option<Bom>,
 }
 
 impl DocumentParser {
     pub(crate) fn parse_reader<R: Read>(reader: R, opts: ReadOptions) -> Result<Document> {
         let doc = Document::new();
         let element_stack = vec![doc.container()];
+        // The five predefined entities (amp/lt/gt/quot/apos) are recognized directly by
+        // `expand_entities` and don't need to be seeded here; this map only ever holds
+        // entities declared in the document's own internal DTD subset.
         let mut parser = DocumentParser {
             document: doc,
             read_opts: opts,
             encoding: None,
             element_stack: element_stack,
+            entities: HashMap::new(),
+            space_stack: vec![false],
+            bom: None,
         };
         parser.parse_start(reader)?;
+        parser.document.entities = parser.entities;
+        parser.document.bom = parser.bom;
         Ok(parser.document)
     }
 
@@ -186,6 +360,9 @@ impl DocumentParser {
             }
             None => None,
         };
+        // Recorded so that serializing the document back out can route it through an
+        // `EncodeWriter` in this same encoding instead of always writing UTF-8.
+        self.document.encoding = self.encoding.map(|e| e.name().to_string());
         self.document.standalone = match ev.standalone() {
             Some(res) => {
                 let val = std::str::from_utf8(&res?)?.to_lowercase();
@@ -204,29 +381,51 @@ impl DocumentParser {
         Ok(())
     }
 
-    fn create_element(&mut self, parent: Element, ev: &BytesStart) -> Result<Element> {
+    // Returns the newly created element along with the `xml:space` directive it carries,
+    // if any: `Some(true)` for "preserve", `Some(false)` for "default", `None` if the
+    // element doesn't set `xml:space` at all.
+    fn create_element(
+        &mut self,
+        parent: Element,
+        ev: &BytesStart,
+    ) -> Result<(Element, Option<bool>)> {
+        let entities = &self.entities;
         let mut_doc = &mut self.document;
         let full_name = String::from_utf8(ev.name().to_vec())?;
         let element = Element::new(mut_doc, full_name);
         let mut namespaces = HashMap::new();
+        let mut xml_space = None;
         let attributes = element.mut_attributes(mut_doc);
         for attr in ev.attributes() {
             let mut attr = attr?;
             attr.value = Cow::Owned(normalize_space(&attr.value));
             let key = String::from_utf8(attr.key.to_vec())?;
-            let value = String::from_utf8(attr.unescaped_value()?.to_vec())?;
+            let raw_value = String::from_utf8(attr.value.to_vec())?;
+            let mut expanded_len = raw_value.len();
+            let value = expand_entities(&raw_value, entities, 0, &mut expanded_len)?;
             if key == "xmlns" {
                 namespaces.insert(String::new(), value);
                 continue;
             } else if let Some(prefix) = key.strip_prefix("xmlns:") {
                 namespaces.insert(prefix.to_owned(), value);
                 continue;
+            } else if key == "xml:space" {
+                xml_space = Some(match value.as_str() {
+                    "preserve" => true,
+                    "default" => false,
+                    _ => {
+                        return Err(Error::MalformedXML(format!(
+                            "Invalid xml:space value '{}'",
+                            value
+                        )))
+                    }
+                });
             }
             attributes.insert(key, value);
         }
         element.mut_namespace_decls(mut_doc).extend(namespaces);
         parent.push_child(mut_doc, Node::Element(element)).unwrap();
-        Ok(element)
+        Ok((element, xml_space))
     }
 
     // Returns true if document parsing is finished.
@@ -237,12 +436,15 @@ impl DocumentParser {
         match event {
             Event::Start(ref ev) => {
                 let parent = *self.element_stack.last().unwrap();
-                let element = self.create_element(parent, ev)?;
+                let (element, xml_space) = self.create_element(parent, ev)?;
+                let inherited = *self.space_stack.last().unwrap();
+                self.space_stack.push(xml_space.unwrap_or(inherited));
                 self.element_stack.push(element);
                 Ok(false)
             }
             Event::End(_) => {
                 let elem = self.element_stack.pop().unwrap(); // quick-xml checks if tag names match for us
+                self.space_stack.pop();
                 if self.read_opts.empty_text_node {
                     // distinguish <tag></tag> and <tag />
                     if !elem.has_children(&mut self.document) {
@@ -258,7 +460,27 @@ impl DocumentParser {
                 Ok(false)
             }
             Event::Text(ev) => {
-                let content = String::from_utf8(ev.to_vec())?;
+                let raw = String::from_utf8(ev.to_vec())?;
+                // With quick-xml's own trimming off, it still emits a zero-length
+                // `Text("")` event for every tag-to-tag gap that has no characters at
+                // all in it (e.g. back-to-back tags). That's reader bookkeeping, not
+                // content, so skip it unconditionally - even under `xml:space="preserve"`,
+                // which should only preserve whitespace that's actually in the document.
+                if raw.is_empty() {
+                    return Ok(false);
+                }
+                let mut expanded_len = raw.len();
+                let expanded = expand_entities(&raw, &self.entities, 0, &mut expanded_len)?;
+                let preserve = *self.space_stack.last().unwrap();
+                let content = if !preserve && self.read_opts.trim_text {
+                    let trimmed = trim_border_whitespace(&expanded);
+                    if trimmed.is_empty() {
+                        return Ok(false);
+                    }
+                    trimmed.to_string()
+                } else {
+                    expanded
+                };
                 let node = Node::Text(content);
                 let parent = *self.element_stack.last().unwrap();
                 parent.push_child(&mut self.document, node).unwrap();
@@ -266,6 +488,11 @@ impl DocumentParser {
             }
             Event::DocType(ev) => {
                 let content = String::from_utf8(ev.to_vec())?;
+                if let Some(subset) = extract_internal_subset(&content) {
+                    for (name, value) in parse_entity_declarations(subset)? {
+                        self.entities.insert(name, value);
+                    }
+                }
                 let node = Node::DocType(content);
                 let parent = *self.element_stack.last().unwrap();
                 parent.push_child(&mut self.document, node).unwrap();
@@ -306,27 +533,11 @@ impl DocumentParser {
         decodereader: &mut DecodeReader<R>,
     ) -> Result<Option<&'static Encoding>> {
         let bytes = decodereader.fill_buf()?;
-        let encoding = match bytes {
-            [0x3c, 0x3f, ..] => None, // UTF-8 '<?'
-            [0xfe, 0xff, ..] => {
-                // UTF-16 BE BOM
-                decodereader.consume(2);
-                Some(UTF_16BE)
-            }
-            [0xff, 0xfe, ..] => {
-                // UTF-16 LE BOM
-                decodereader.consume(2);
-                Some(UTF_16LE)
-            }
-            [0xef, 0xbb, 0xbf, ..] => {
-                // UTF-8 BOM
-                decodereader.consume(3);
-                None
-            }
-            [0x00, 0x3c, 0x00, 0x3f, ..] => Some(UTF_16BE),
-            [0x3c, 0x00, 0x3f, 0x00, ..] => Some(UTF_16LE),
-            _ => None, // Try decoding it with UTF-8
-        };
+        let (encoding, bom, consumed) = sniff_bom(bytes, self.read_opts.detect_bom);
+        if consumed > 0 {
+            decodereader.consume(consumed);
+        }
+        self.bom = bom;
         Ok(encoding)
     }
 
@@ -338,11 +549,25 @@ impl DocumentParser {
             init_encoding = Some(Encoding::for_label(enc.as_bytes()).ok_or(Error::CannotDecode)?)
         }
         decodereader.set_encoding(init_encoding);
+        decodereader.set_strict(self.read_opts.strict);
         let mut xmlreader = Reader::from_reader(decodereader);
-        xmlreader.trim_text(self.read_opts.trim_text);
+        // Trimming is handled ourselves in the `Event::Text` branch so that
+        // `xml:space="preserve"` can override it per element.
+        xmlreader.trim_text(false);
 
         let mut buf = Vec::with_capacity(200);
-        let event = xmlreader.read_event(&mut buf)?;
+        let mut event = xmlreader.read_event(&mut buf)?;
+        // With `trim_text(false)`, quick-xml still hands back a zero-length `Text("")`
+        // event for the (empty) gap before the very first real event - there's nothing
+        // there to trim, but unlike `trim_text(true)` it no longer swallows it for us.
+        // Skip past any such events so the declaration check below sees the real one.
+        while let Event::Text(ref ev) = event {
+            if !ev.is_empty() {
+                break;
+            }
+            buf.clear();
+            event = xmlreader.read_event(&mut buf)?;
+        }
         if let Event::Decl(ev) = event {
             self.handle_decl(&ev)?;
             // Encoding::for_label("UTF-16") defaults to UTF-16 LE, even though it could be UTF-16 BE
@@ -352,7 +577,7 @@ impl DocumentParser {
                 let mut decode_reader = xmlreader.into_underlying_reader();
                 decode_reader.set_encoding(self.encoding);
                 xmlreader = Reader::from_reader(decode_reader);
-                xmlreader.trim_text(self.read_opts.trim_text);
+                xmlreader.trim_text(false);
             }
         } else if self.read_opts.require_decl {
             return Err(Error::MalformedXML(
@@ -377,6 +602,163 @@ impl DocumentParser {
     }
 }
 
+// Sniffs a leading byte-order mark or BOM-less UTF-16 pattern from a document's first
+// bytes. Returns the encoding to decode with (if any), the `Bom` that was detected and
+// should be consumed (if any), and how many leading bytes that BOM occupies. The
+// BOM-less UTF-16 heuristic always runs; `detect_bom` only gates actual BOM sniffing.
+fn sniff_bom(bytes: &[u8], detect_bom: bool) -> (Option<&'static Encoding>, Option<Bom>, usize) {
+    match bytes {
+        [0x3c, 0x3f, ..] => (None, None, 0), // UTF-8 '<?'
+        [0xfe, 0xff, ..] if detect_bom => (Some(UTF_16BE), Some(Bom::Utf16Be), 2),
+        [0xff, 0xfe, ..] if detect_bom => (Some(UTF_16LE), Some(Bom::Utf16Le), 2),
+        [0xef, 0xbb, 0xbf, ..] if detect_bom => (None, Some(Bom::Utf8), 3),
+        [0x00, 0x3c, 0x00, 0x3f, ..] => (Some(UTF_16BE), None, 0),
+        [0x3c, 0x00, 0x3f, 0x00, ..] => (Some(UTF_16LE), None, 0),
+        _ => (None, None, 0), // Try decoding it with UTF-8
+    }
+}
+
+// Trims the leading and trailing XML whitespace characters (#x20, #x9, #xD, #xA)
+// from a text node, mirroring quick-xml's own `trim_text` behavior.
+fn trim_border_whitespace(s: &str) -> &str {
+    s.trim_matches(|c: char| matches!(c, ' ' | '\t' | '\r' | '\n'))
+}
+
+// Returns the text between the first '[' and the last ']' in a DOCTYPE's
+// content, i.e. the internal subset, if the DOCTYPE declares one.
+fn extract_internal_subset(doctype: &str) -> Option<&str> {
+    let start = doctype.find('[')?;
+    let end = doctype.rfind(']')?;
+    if end > start {
+        Some(&doctype[start + 1..end])
+    } else {
+        None
+    }
+}
+
+// Scans an internal subset for `<!ENTITY name "value">` general entity
+// declarations. Parameter entities (`<!ENTITY % ...>`) and external entities
+// (`SYSTEM`/`PUBLIC`) are not supported and are silently skipped.
+fn parse_entity_declarations(subset: &str) -> Result<Vec<(String, String)>> {
+    let mut declarations = Vec::new();
+    let mut rest = subset;
+    while let Some(start) = rest.find("<!ENTITY") {
+        let after = &rest[start + "<!ENTITY".len()..];
+        let end = after
+            .find('>')
+            .ok_or_else(|| Error::MalformedXML("Unterminated <!ENTITY declaration".to_string()))?;
+        let body = after[..end].trim();
+        rest = &after[end + 1..];
+
+        if body.starts_with('%') {
+            continue; // Parameter entity, not supported.
+        }
+
+        let mut parts = body.splitn(2, char::is_whitespace);
+        let name = parts.next().unwrap_or("").trim();
+        let remainder = parts.next().unwrap_or("").trim();
+        if remainder.starts_with("SYSTEM") || remainder.starts_with("PUBLIC") {
+            continue; // External entity, not supported.
+        }
+
+        let value = parse_quoted_literal(remainder).ok_or_else(|| {
+            Error::MalformedXML(format!("Malformed <!ENTITY {}> declaration", name))
+        })?;
+        declarations.push((name.to_string(), value));
+    }
+    Ok(declarations)
+}
+
+// Parses a single `"..."` or `'...'` literal, as used for entity replacement text.
+fn parse_quoted_literal(value: &str) -> Option<String> {
+    let quote = value.chars().next()?;
+    if quote != '"' && quote != '\'' {
+        return None;
+    }
+    let end = value[1..].find(quote)?;
+    Some(value[1..1 + end].to_string())
+}
+
+// The five entities every XML processor understands without a DTD. Their replacement
+// text is inserted literally rather than being re-scanned for further references:
+// `amp`'s replacement is itself `&`, and feeding that back through the scanner would
+// be seen as the start of a new (unterminated) reference.
+fn predefined_entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        _ => None,
+    }
+}
+
+// Recursively expands `&name;` and `&#...;` references in `input` using `entities`,
+// rejecting undefined entities and bailing out once expansion exceeds
+// `MAX_ENTITY_DEPTH` levels of nesting or `MAX_EXPANDED_ENTITY_LEN` total bytes,
+// which protects against "billion laughs" style expansion bombs.
+fn expand_entities(
+    input: &str,
+    entities: &HashMap<String, String>,
+    depth: usize,
+    expanded_len: &mut usize,
+) -> Result<String> {
+    if depth > MAX_ENTITY_DEPTH {
+        return Err(Error::MalformedXML(
+            "Entity reference nesting exceeds maximum depth".to_string(),
+        ));
+    }
+
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(amp) = rest.find('&') {
+        output.push_str(&rest[..amp]);
+        let after_amp = &rest[amp + 1..];
+        let semi = after_amp
+            .find(';')
+            .ok_or_else(|| Error::MalformedXML("Unterminated entity reference".to_string()))?;
+        let reference = &after_amp[..semi];
+        let before_len = output.len();
+
+        if let Some(numeric) = reference.strip_prefix('#') {
+            let code_point = if let Some(hex) = numeric
+                .strip_prefix('x')
+                .or_else(|| numeric.strip_prefix('X'))
+            {
+                u32::from_str_radix(hex, 16).ok()
+            } else {
+                numeric.parse::<u32>().ok()
+            }
+            .and_then(char::from_u32)
+            .ok_or_else(|| {
+                Error::MalformedXML(format!("Invalid character reference &{};", reference))
+            })?;
+            output.push(code_point);
+        } else if let Some(ch) = predefined_entity(reference) {
+            output.push(ch);
+        } else if let Some(replacement) = entities.get(reference) {
+            let expanded = expand_entities(replacement, entities, depth + 1, expanded_len)?;
+            output.push_str(&expanded);
+        } else {
+            return Err(Error::MalformedXML(format!(
+                "Reference to undefined entity '&{};'",
+                reference
+            )));
+        }
+
+        *expanded_len += output.len() - before_len;
+        if *expanded_len > MAX_EXPANDED_ENTITY_LEN {
+            return Err(Error::MalformedXML(
+                "Entity expansion exceeded maximum total length".to_string(),
+            ));
+        }
+        rest = &after_amp[semi + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
 /// #xD(\r), #xA(\n), #x9(\t) is normalized into #x20.
 /// Leading and trailing spaces(#x20) are discarded
 /// and sequence of spaces are replaced by a single space.
@@ -405,3 +787,179 @@ pub fn normalize_space(bytes: &[u8]) -> Vec<u8> {
     }
     normalized
 }
+
+#[cfg(test)]
+mod entity_tests {
+    use super::*;
+
+    #[test]
+    fn predefined_entities_are_not_rescanned() {
+        let entities = HashMap::new();
+        let mut len = 0;
+        assert_eq!(
+            expand_entities("Fish &amp; Chips", &entities, 0, &mut len).unwrap(),
+            "Fish & Chips"
+        );
+        let mut len = 0;
+        assert_eq!(
+            expand_entities("&amp;&amp;", &entities, 0, &mut len).unwrap(),
+            "&&"
+        );
+    }
+
+    #[test]
+    fn custom_entity_is_expanded() {
+        let mut entities = HashMap::new();
+        entities.insert("foo".to_string(), "bar".to_string());
+        let mut len = 0;
+        assert_eq!(
+            expand_entities("a &foo; b", &entities, 0, &mut len).unwrap(),
+            "a bar b"
+        );
+    }
+
+    #[test]
+    fn undefined_entity_is_an_error() {
+        let entities = HashMap::new();
+        let mut len = 0;
+        assert!(expand_entities("&bogus;", &entities, 0, &mut len).is_err());
+    }
+
+    #[test]
+    fn many_small_entities_dont_trip_the_length_cap() {
+        let mut entities = HashMap::new();
+        entities.insert("x".to_string(), "a".to_string());
+        let input = "&x;".repeat(2000);
+        let mut len = 0;
+        let expanded = expand_entities(&input, &entities, 0, &mut len).unwrap();
+        assert_eq!(expanded.len(), 2000);
+        assert_eq!(len, 2000);
+    }
+
+    #[test]
+    fn expansion_bomb_is_rejected() {
+        let mut entities = HashMap::new();
+        entities.insert("a".to_string(), "x".repeat(1000));
+        entities.insert("b".to_string(), "&a;".repeat(1000));
+        entities.insert("c".to_string(), "&b;".repeat(1000));
+        let mut len = 0;
+        assert!(expand_entities("&c;", &entities, 0, &mut len).is_err());
+    }
+}
+
+#[cfg(test)]
+mod bom_tests {
+    use super::*;
+
+    #[test]
+    fn detects_and_consumes_utf8_bom() {
+        let (encoding, bom, consumed) = sniff_bom(b"\xef\xbb\xbf<?xml", true);
+        assert_eq!(encoding, None);
+        assert_eq!(bom, Some(Bom::Utf8));
+        assert_eq!(consumed, 3);
+    }
+
+    #[test]
+    fn detects_and_consumes_utf16_boms() {
+        let (encoding, bom, consumed) = sniff_bom(b"\xfe\xff\x00<", true);
+        assert_eq!(encoding, Some(UTF_16BE));
+        assert_eq!(bom, Some(Bom::Utf16Be));
+        assert_eq!(consumed, 2);
+
+        let (encoding, bom, consumed) = sniff_bom(b"\xff\xfe<\x00", true);
+        assert_eq!(encoding, Some(UTF_16LE));
+        assert_eq!(bom, Some(Bom::Utf16Le));
+        assert_eq!(consumed, 2);
+    }
+
+    #[test]
+    fn detect_bom_false_skips_bom_but_not_bomless_utf16_heuristic() {
+        // A real BOM is left untouched when detection is disabled.
+        let (encoding, bom, consumed) = sniff_bom(b"\xef\xbb\xbf<?xml", false);
+        assert_eq!(encoding, None);
+        assert_eq!(bom, None);
+        assert_eq!(consumed, 0);
+
+        // But the BOM-less UTF-16 heuristic (no BOM present at all) still fires.
+        let (encoding, bom, consumed) = sniff_bom(b"\x00<\x00?", false);
+        assert_eq!(encoding, Some(UTF_16BE));
+        assert_eq!(bom, None);
+        assert_eq!(consumed, 0);
+
+        let (encoding, bom, consumed) = sniff_bom(b"<\x00?\x00", false);
+        assert_eq!(encoding, Some(UTF_16LE));
+        assert_eq!(bom, None);
+        assert_eq!(consumed, 0);
+    }
+
+    #[test]
+    fn plain_utf8_document_has_no_bom() {
+        let (encoding, bom, consumed) = sniff_bom(b"<?xml version=\"1.0\"?>", true);
+        assert_eq!(encoding, None);
+        assert_eq!(bom, None);
+        assert_eq!(consumed, 0);
+    }
+}
+
+#[cfg(test)]
+mod decode_reader_tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn strict_mode_errors_on_invalid_bytes() {
+        let mut reader = DecodeReader::new(
+            Cursor::new(vec![0xff, b'a']),
+            Some(UTF_8.new_decoder_without_bom_handling()),
+        );
+        reader.set_strict(true);
+        let err = reader.fill_buf().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<NonDecodableError>()
+            .is_some());
+    }
+
+    #[test]
+    fn non_strict_mode_replaces_invalid_bytes() {
+        let mut reader = DecodeReader::new(
+            Cursor::new(vec![0xff, b'a']),
+            Some(UTF_8.new_decoder_without_bom_handling()),
+        );
+        let bytes = reader.fill_buf().unwrap();
+        let text = std::str::from_utf8(bytes).unwrap();
+        assert!(text.contains('\u{FFFD}'));
+    }
+}
+
+#[cfg(test)]
+mod encode_writer_tests {
+    use super::*;
+
+    #[test]
+    fn encodes_ascii_text() {
+        let mut writer = EncodeWriter::new(Vec::new(), UTF_16LE.new_encoder());
+        writer.write_all(b"hi").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.inner, vec![b'h', 0x00, b'i', 0x00]);
+    }
+
+    #[test]
+    fn buffers_a_multibyte_char_split_across_writes() {
+        let mut writer = EncodeWriter::new(Vec::new(), UTF_16LE.new_encoder());
+        let bytes = "\u{e9}".as_bytes(); // 0xc3, 0xa9 in UTF-8
+        writer.write_all(&bytes[..1]).unwrap();
+        writer.write_all(&bytes[1..]).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.inner, vec![0xe9, 0x00]);
+    }
+
+    #[test]
+    fn flush_errors_on_truncated_utf8_sequence() {
+        let mut writer = EncodeWriter::new(Vec::new(), UTF_16LE.new_encoder());
+        writer.write_all(&"\u{e9}".as_bytes()[..1]).unwrap();
+        assert!(writer.flush().is_err());
+    }
+}